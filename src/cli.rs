@@ -1,3 +1,4 @@
+use crate::shell;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
@@ -13,6 +14,28 @@ pub struct Cli {
     #[arg(short, long)]
     pub verbose: bool,
 
+    /// Don't log shell commands before running them
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Show shell command output live while it runs
+    #[arg(short, long)]
+    pub stream: bool,
+
+    /// Safety mode for shell commands, overriding the file's `shell_mode`
+    #[arg(long, value_enum)]
+    pub shell_mode: Option<shell::Mode>,
+
+    /// Pattern of commands allowed in `allowlist` mode, replacing the file's
+    /// `allow_shell` patterns
+    #[arg(long, value_name = "PATTERN")]
+    pub allow_shell: Vec<String>,
+
+    /// Interpreter for shell commands, e.g. `bash` or `bash -o pipefail`,
+    /// overriding the file's `interpreter`
+    #[arg(long)]
+    pub interpreter: Option<String>,
+
     /// Subcommands
     #[command(subcommand)]
     pub command: Command,
@@ -0,0 +1,57 @@
+use crate::Value;
+use serde_json::{Map, Number, Value as Json};
+
+pub fn parse(source: &str) -> Result<Value, String> {
+    let json: Json =
+        serde_json::from_str(source).map_err(|err| format!("json_parse error: {err}"))?;
+    Ok(from_json(json))
+}
+
+pub fn emit(value: &Value) -> Result<String, String> {
+    let json = to_json(value)?;
+    serde_json::to_string_pretty(&json).map_err(|err| format!("json_emit error: {err}"))
+}
+
+fn from_json(json: Json) -> Value {
+    match json {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Bool(b),
+        Json::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Value::Int(i)
+            } else if let Some(u) = n.as_u64() {
+                Value::U64(u)
+            } else {
+                Value::Float(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Json::String(s) => Value::Str(s),
+        Json::Array(items) => Value::List(items.into_iter().map(from_json).collect()),
+        Json::Object(map) => Value::Map(map.into_iter().map(|(k, v)| (k, from_json(v))).collect()),
+    }
+}
+
+fn to_json(value: &Value) -> Result<Json, String> {
+    Ok(match value {
+        Value::Nil => Json::Null,
+        Value::Bool(b) => Json::Bool(*b),
+        Value::Str(s) => Json::String(s.clone()),
+        Value::Int(i) => Json::from(*i),
+        Value::I32(i) => Json::from(*i),
+        Value::U32(i) => Json::from(*i),
+        Value::U64(i) => Json::from(*i),
+        Value::Float(f) => match Number::from_f64(*f) {
+            Some(n) => Json::Number(n),
+            None => return Err(format!("json_emit error: {f} is not a valid JSON number")),
+        },
+        Value::List(items) => Json::Array(items.iter().map(to_json).collect::<Result<_, _>>()?),
+        Value::Job(_) => return Err("json_emit error: a job handle is not valid JSON".into()),
+        Value::Map(map) => {
+            let mut object = Map::new();
+            for (k, v) in map {
+                object.insert(k.clone(), to_json(v)?);
+            }
+            Json::Object(object)
+        }
+    })
+}
@@ -6,7 +6,7 @@ mod tests;
 fn is_keyword_default(ident: &str) -> bool {
     matches!(
         ident,
-        "let" | "fn" | "if" | "else" | "return" | "while" | "for"
+        "let" | "fn" | "if" | "elif" | "else" | "return" | "break" | "continue" | "while" | "for"
     )
 }
 
@@ -53,6 +53,18 @@ pub struct LexError {
     pub message: String,
 }
 
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.loc, self.message)
+    }
+}
+
+impl From<LexError> for String {
+    fn from(err: LexError) -> Self {
+        err.to_string()
+    }
+}
+
 impl<'src> Lexer<'src> {
     pub fn new(source: &'src str) -> Self {
         Self {
@@ -164,6 +176,7 @@ impl<'src> Lexer<'src> {
                 b'@' => return self.lex_macro(begin),
 
                 b',' => Token::new(TokenKind::Comma, loc, self.source[begin..self.pos].into()),
+                b'.' => Token::new(TokenKind::Dot, loc, self.source[begin..self.pos].into()),
                 b';' => Token::new(
                     TokenKind::SemiColon,
                     loc,
@@ -264,6 +277,18 @@ impl<'src> Lexer<'src> {
             self.advance();
         }
 
+        if self.read_char() == b'.' && self.data.get(self.pos + 1).is_some_and(u8::is_ascii_digit) {
+            self.advance();
+            while let b'0'..=b'9' = self.read_char() {
+                self.advance();
+            }
+            return Ok(Token::new(
+                TokenKind::RealNumber,
+                loc,
+                self.source[begin..self.pos].into(),
+            ));
+        }
+
         let suffix_start = self.pos;
         let suffix = self.peek_suffix();
 
@@ -300,8 +325,16 @@ impl<'src> Lexer<'src> {
         ))
     }
 
+    /// Lexes a string literal. If it contains `{name}` placeholders the
+    /// token is a `FormatString` whose source keeps the placeholders and
+    /// doubles literal braces (`{{`, `}}`); escape a brace with `\{`. A brace
+    /// right after `$`, `%` or a backslash and letter is never a placeholder,
+    /// so shell `${VAR}`, curl's `%{http_code}` and regex classes such as
+    /// `\p{Greek}` pass through unchanged.
     fn lex_string(&mut self, _begin: usize) -> Result<Token, LexError> {
         let mut buffer = String::new();
+        let mut format = String::new();
+        let mut has_placeholder = false;
         let loc = self.loc();
         loop {
             let ch = self.read_char();
@@ -319,27 +352,63 @@ impl<'src> Lexer<'src> {
                 b'\\' => {
                     self.advance();
                     let esc = self.read_char();
-                    match esc {
-                        b'r' => buffer.push('\r'),
-                        b'n' => buffer.push('\n'),
-                        b'"' => buffer.push('"'),
-                        b'\'' => buffer.push('\''),
-                        b'\\' => buffer.push('\\'),
-                        b'0' => buffer.push('\0'),
+                    let ch = match esc {
+                        b'r' => '\r',
+                        b'n' => '\n',
+                        b'"' => '"',
+                        b'\'' => '\'',
+                        b'\\' => '\\',
+                        b'0' => '\0',
+                        b'{' => '{',
+                        b'}' => '}',
                         _ => {
                             return Err(LexError {
                                 loc,
                                 message: format!("invalid escape sequence: \\{}", esc as char),
                             });
                         }
-                    }
+                    };
+                    push_literal(&mut buffer, &mut format, ch);
                 }
-                _ => buffer.push(ch as char),
+                b'{' if !ends_in_brace_prefix(&buffer) && self.peek_placeholder().is_some() => {
+                    let name = self.peek_placeholder().unwrap();
+                    buffer.push('{');
+                    buffer.push_str(name);
+                    buffer.push('}');
+                    format.push('{');
+                    format.push_str(name);
+                    format.push('}');
+                    has_placeholder = true;
+                    self.advance_n(name.len() + 1);
+                }
+                _ => push_literal(&mut buffer, &mut format, ch as char),
             }
             self.advance();
         }
 
-        Ok(Token::new(TokenKind::StringLiteral, loc, buffer))
+        if has_placeholder {
+            Ok(Token::new(TokenKind::FormatString, loc, format))
+        } else {
+            Ok(Token::new(TokenKind::StringLiteral, loc, buffer))
+        }
+    }
+
+    /// Returns `name` if the input at the cursor is `{name}` with an
+    /// identifier inside the braces.
+    fn peek_placeholder(&self) -> Option<&'src str> {
+        let start = self.pos + 1;
+        let mut end = start;
+        while end < self.data.len()
+            && (self.data[end].is_ascii_alphanumeric() || self.data[end] == b'_')
+        {
+            end += 1;
+        }
+        let starts_ident = start < end && !self.data[start].is_ascii_digit();
+        if starts_ident && self.data.get(end) == Some(&b'}') {
+            Some(&self.source[start..end])
+        } else {
+            None
+        }
     }
 
     pub fn loc(&self) -> Loc {
@@ -401,6 +470,25 @@ impl<'src> Lexer<'src> {
     }
 }
 
+/// Whether `{` after `buffer` belongs to something else than a placeholder:
+/// `${`, `%{` or a regex escape such as `\p{`
+fn ends_in_brace_prefix(buffer: &str) -> bool {
+    let mut rev = buffer.chars().rev();
+    match (rev.next(), rev.next()) {
+        (Some('$' | '%'), _) => true,
+        (Some(c), Some('\\')) => c.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+fn push_literal(buffer: &mut String, format: &mut String, ch: char) {
+    buffer.push(ch);
+    format.push(ch);
+    if ch == '{' || ch == '}' {
+        format.push(ch);
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy)]
 pub struct Span<T> {
     _marker: PhantomData<T>,
@@ -486,6 +574,7 @@ pub enum TokenKind {
     UnsignedIntegerNumber,
     LongUnsignedIntegerNumber,
     StringLiteral,
+    FormatString,
     CharacterLiteral,
 
     Dot,
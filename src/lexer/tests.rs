@@ -0,0 +1,112 @@
+use super::*;
+
+fn kinds(source: &str) -> Vec<TokenKind> {
+    let mut l = Lexer::new(source);
+    let mut kinds = Vec::new();
+    loop {
+        let t = l.next_token().unwrap();
+        if t.is_eof() {
+            break;
+        }
+        kinds.push(t.kind);
+    }
+    kinds
+}
+
+#[test]
+fn lex_block() {
+    use TokenKind::*;
+    assert_eq!(
+        kinds("main { \"hi\" echo }"),
+        vec![Identifier, OpenBrace, StringLiteral, Identifier, CloseBrace]
+    );
+}
+
+#[test]
+fn lex_keywords() {
+    use TokenKind::*;
+    assert_eq!(
+        kinds("if while let x"),
+        vec![Keyword, Keyword, Keyword, Identifier]
+    );
+}
+
+#[test]
+fn lex_list_literal() {
+    use TokenKind::*;
+    assert_eq!(
+        kinds("[ \"a\" 1 ]"),
+        vec![OpenSquare, StringLiteral, Integer, CloseSquare]
+    );
+}
+
+#[test]
+fn lex_string_escapes() {
+    let mut l = Lexer::new("\"a\\n\\\"b\\\"\"");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::StringLiteral);
+    assert_eq!(t.source, "a\n\"b\"");
+}
+
+#[test]
+fn lex_unterminated_string() {
+    let mut l = Lexer::new("\"abc");
+    assert!(l.next_token().is_err());
+}
+
+#[test]
+fn lex_real_number() {
+    let mut l = Lexer::new("3.25 4 .");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::RealNumber);
+    assert_eq!(t.source, "3.25");
+    assert_eq!(l.next_token().unwrap().kind, TokenKind::Integer);
+    assert_eq!(l.next_token().unwrap().kind, TokenKind::Dot);
+}
+
+#[test]
+fn lex_integer_suffixes() {
+    use TokenKind::*;
+    assert_eq!(
+        kinds("1 2i32 3u32 4i64 5u64"),
+        vec![
+            Integer,
+            IntegerNumber,
+            UnsignedIntegerNumber,
+            LongIntegerNumber,
+            LongUnsignedIntegerNumber
+        ]
+    );
+    let mut l = Lexer::new("420u32");
+    assert_eq!(l.next_token().unwrap().source, "420");
+}
+
+#[test]
+fn lex_format_string() {
+    let mut l = Lexer::new("\"v{version} {{.x}} \\{a}\" \"{1} {a b}\"");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::FormatString);
+    assert_eq!(t.source, "v{version} {{{{.x}}}} {{a}}");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::StringLiteral);
+    assert_eq!(t.source, "{1} {a b}");
+}
+
+#[test]
+fn lex_shell_braces_stay_literal() {
+    let mut l = Lexer::new("\"${HOME}\" \"%{http_code} {y}\"");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::StringLiteral);
+    assert_eq!(t.source, "${HOME}");
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::FormatString);
+    assert_eq!(t.source, "%{{http_code}} {y}");
+}
+
+#[test]
+fn lex_regex_classes_stay_literal() {
+    let mut l = Lexer::new(r#""^\\p{Greek}\\P{Alphabetic}""#);
+    let t = l.next_token().unwrap();
+    assert_eq!(t.kind, TokenKind::StringLiteral);
+    assert_eq!(t.source, r"^\p{Greek}\P{Alphabetic}");
+}
@@ -1,11 +1,15 @@
 use clap::Parser;
 use lexer::{PeekableLexer, Token, TokenKind};
-use std::collections::HashSet;
-use std::path::Path;
-use std::{collections::HashMap, fs, process};
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{collections::HashMap, fmt, fs, process};
 
 pub mod cli;
+pub mod json;
 pub mod lexer;
+pub mod shell;
+pub mod toml;
 
 fn main() {
     let cli = cli::Cli::parse();
@@ -15,8 +19,16 @@ fn main() {
         process::exit(1);
     }
 
-    let blocks = match parse_file(&cli.file) {
-        Ok(b) => b,
+    let ProjFile { blocks, settings } = match parse_file(&cli.file) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Parse error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let opts = match shell_options(&cli, &settings) {
+        Ok(opts) => opts,
         Err(e) => {
             eprintln!("Parse error: {}", e);
             process::exit(1);
@@ -26,10 +38,10 @@ fn main() {
     match cli.command {
         cli::Command::Run { directive } => {
             if cli.verbose {
-                println!("Running directive: {}", directive);
+                eprintln!("Running directive: {}", directive);
             }
 
-            if let Err(e) = run_commands(directive, blocks) {
+            if let Err(e) = run_commands(directive, blocks, opts) {
                 eprintln!("Execution error: {}", e);
                 process::exit(1);
             }
@@ -43,24 +55,92 @@ fn main() {
     }
 }
 
+/// Longest list `range` builds; `n for` counts without a list
+const MAX_RANGE: i64 = 10_000_000;
+
 macro_rules! error {
     ($($arg:tt)*) => {{
         return Err(format!("{}", format_args!($($arg)*)))
     }};
 }
 
-fn parse_file<P: AsRef<Path>>(filepath: P) -> Result<HashMap<String, Block>, String> {
+#[derive(Debug, Default)]
+struct ProjFile {
+    blocks: HashMap<String, Block>,
+    settings: Settings,
+}
+
+/// Top-level `name "value"` lines of a .proj file
+#[derive(Debug, Default)]
+struct Settings {
+    shell_mode: Option<shell::Mode>,
+    allow_shell: Vec<String>,
+    interpreter: Option<String>,
+}
+
+fn shell_options(cli: &cli::Cli, settings: &Settings) -> Result<shell::Options, String> {
+    let mut opts = shell::Options {
+        stream: cli.stream,
+        quiet: cli.quiet,
+        mode: cli.shell_mode.or(settings.shell_mode).unwrap_or_default(),
+        ..Default::default()
+    };
+    // Safety options given on the command line replace the file's, so an
+    // untrusted file can't widen the allowlist or pick the program that
+    // runs the vetted command strings
+    let cli_safety = cli.shell_mode.is_some() || !cli.allow_shell.is_empty();
+    let (allow, interpreter) = if cli_safety {
+        (&cli.allow_shell, cli.interpreter.as_ref())
+    } else {
+        let interpreter = cli.interpreter.as_ref().or(settings.interpreter.as_ref());
+        (&settings.allow_shell, interpreter)
+    };
+    for pattern in allow {
+        opts.allow_pattern(pattern)?;
+    }
+    if let Some(spec) = interpreter {
+        opts.interpreter = shell::parse_interpreter(spec)?;
+    }
+    Ok(opts)
+}
+
+fn parse_setting(
+    l: &mut PeekableLexer<'_>,
+    name: Token,
+    settings: &mut Settings,
+) -> Result<(), String> {
+    let value = expect_token(l, TokenKind::StringLiteral)?;
+    match name.source.as_str() {
+        "shell_mode" => {
+            use clap::ValueEnum;
+            match shell::Mode::from_str(&value.source, true) {
+                Ok(mode) => settings.shell_mode = Some(mode),
+                Err(_) => error!("{} Unknown shell_mode '{}'", value.loc, value.source),
+            }
+        }
+        "allow_shell" => settings.allow_shell.push(value.source),
+        "interpreter" => settings.interpreter = Some(value.source),
+        _ => error!("{} Unknown setting '{}'", name.loc, name.source),
+    }
+    Ok(())
+}
+
+fn parse_file<P: AsRef<Path>>(filepath: P) -> Result<ProjFile, String> {
     let source = fs::read_to_string(&filepath).map_err(|err| format!("{err}"))?;
     let mut l = PeekableLexer::new(&source);
     let mut blocks = HashMap::default();
+    let mut settings = Settings::default();
 
     loop {
-        let t = l.next_token();
+        let t = l.next_token()?;
         if t.is_eof() {
             break;
         }
 
         match t.kind {
+            TokenKind::Identifier if l.peek_token()?.kind != TokenKind::OpenBrace => {
+                parse_setting(&mut l, t, &mut settings)?;
+            }
             TokenKind::Identifier => {
                 let block_name = t.source;
                 let block: Block = parse_block(&mut l, &blocks)?;
@@ -81,11 +161,11 @@ fn parse_file<P: AsRef<Path>>(filepath: P) -> Result<HashMap<String, Block>, Str
         }
     }
 
-    Ok(blocks)
+    Ok(ProjFile { blocks, settings })
 }
 
 fn expect_token(l: &mut PeekableLexer<'_>, kind: TokenKind) -> Result<Token, String> {
-    let token = l.next_token();
+    let token = l.next_token()?;
     if token.kind != kind {
         error!(
             "{} Unexpected token {}, Expect: {:?}",
@@ -99,33 +179,158 @@ fn parse_block(
     l: &mut PeekableLexer<'_>,
     blocks: &HashMap<String, Block>,
 ) -> Result<Block, String> {
-    let mut block = Block::default();
     expect_token(l, TokenKind::OpenBrace)?;
+    parse_commands(l, blocks, TokenKind::CloseBrace)
+}
+
+fn parse_commands(
+    l: &mut PeekableLexer<'_>,
+    blocks: &HashMap<String, Block>,
+    close: TokenKind,
+) -> Result<Block, String> {
+    let mut block = Block::default();
     loop {
-        let p = l.peek_token();
-        if p.kind == TokenKind::CloseBrace {
-            l.next_token();
+        let p = l.peek_token()?;
+        if p.kind == close {
+            l.next_token()?;
             break;
         }
-        let t = l.next_token();
+        if p.is_eof() {
+            error!("{} Unexpected end of file, Expect: {:?}", p.loc, close);
+        }
+        let t = l.next_token()?;
         match t.kind {
             TokenKind::StringLiteral => {
                 block.commands.push(Command::PushStr(t.source));
             }
-            TokenKind::Integer => {
+            TokenKind::FormatString => {
+                block
+                    .commands
+                    .push(Command::PushFormat(parse_format(&t.source)));
+            }
+            TokenKind::Integer | TokenKind::LongIntegerNumber => {
                 block.commands.push(Command::PushInt(
                     t.source.parse().map_err(|err| format!("{err}"))?,
                 ));
             }
+            TokenKind::IntegerNumber => {
+                block.commands.push(Command::PushI32(
+                    t.source.parse().map_err(|err| format!("{err}"))?,
+                ));
+            }
+            TokenKind::UnsignedIntegerNumber => {
+                block.commands.push(Command::PushU32(
+                    t.source.parse().map_err(|err| format!("{err}"))?,
+                ));
+            }
+            TokenKind::LongUnsignedIntegerNumber => {
+                block.commands.push(Command::PushU64(
+                    t.source.parse().map_err(|err| format!("{err}"))?,
+                ));
+            }
+            TokenKind::RealNumber => {
+                block.commands.push(Command::PushFloat(
+                    t.source.parse().map_err(|err| format!("{err}"))?,
+                ));
+            }
+            TokenKind::OpenSquare => {
+                let inner = parse_commands(l, blocks, TokenKind::CloseSquare)?;
+                block.deps.extend(inner.deps);
+                block.commands.push(Command::PushList(inner.commands));
+            }
+            TokenKind::Keyword => match t.source.as_str() {
+                "if" => {
+                    let cmd = parse_if(l, blocks, &mut block.deps)?;
+                    block.commands.push(cmd);
+                }
+                "while" => {
+                    let inner = parse_block(l, blocks)?;
+                    block.deps.extend(inner.deps);
+                    block.commands.push(Command::While(inner.commands));
+                }
+                "for" => {
+                    let id_token = expect_token(l, TokenKind::Identifier)?;
+                    let inner = parse_block(l, blocks)?;
+                    block.deps.extend(inner.deps);
+                    block
+                        .commands
+                        .push(Command::For(id_token.source, inner.commands));
+                }
+                "break" => block.commands.push(Command::Break),
+                "continue" => block.commands.push(Command::Continue),
+                "return" => block.commands.push(Command::Return),
+                "let" => {
+                    let id_token = expect_token(l, TokenKind::Identifier)?;
+                    block.commands.push(Command::Store(id_token.source));
+                }
+                _ => error!("{} Unexpected keyword '{}'", t.loc, t.source),
+            },
             TokenKind::Identifier => match t.source.as_str() {
                 "echo" => block.commands.push(Command::Echo),
                 "shell" => block.commands.push(Command::Shell),
+                "exec" => block.commands.push(Command::Exec),
+                "timeout" => block.commands.push(Command::Timeout),
+                "feed" => block.commands.push(Command::Feed),
+                "spawn" => block.commands.push(Command::Spawn),
+                "wait" => block.commands.push(Command::Wait),
+                "kill" => block.commands.push(Command::Kill),
+                "job_status" => block.commands.push(Command::JobStatus),
+                "stream" => block.commands.push(Command::Stream),
+                "quiet" => block.commands.push(Command::Quiet),
+                "cd" => block.commands.push(Command::Cd),
+                "pushd" => block.commands.push(Command::Pushd),
+                "popd" => block.commands.push(Command::Popd),
+                "setenv" => block.commands.push(Command::SetEnv),
+                "getenv" => block.commands.push(Command::GetEnv),
+                "unsetenv" => block.commands.push(Command::UnsetEnv),
+                "run_argv" => block.commands.push(Command::RunArgv),
+                "pipeline" => block.commands.push(Command::Pipeline),
                 "readfile" => block.commands.push(Command::ReadFile),
                 "writefile" => block.commands.push(Command::WriteFile),
+                "json_parse" => block.commands.push(Command::JsonParse),
+                "json_emit" => block.commands.push(Command::JsonEmit),
+                "toml_parse" => block.commands.push(Command::TomlParse),
 
                 "concat" => block.commands.push(Command::Concat),
+                "trim" => block.commands.push(Command::Trim),
+                "replace" => block.commands.push(Command::Replace),
+                "upper" => block.commands.push(Command::Upper),
+                "lower" => block.commands.push(Command::Lower),
+                "starts_with" => block.commands.push(Command::StartsWith),
+                "ends_with" => block.commands.push(Command::EndsWith),
+                "contains" => block.commands.push(Command::Contains),
+                "substr" => block.commands.push(Command::Substr),
+                "lines" => block.commands.push(Command::Lines),
+                "match" => block.commands.push(Command::Match),
+                "captures" => block.commands.push(Command::Captures),
+                "regex_replace" => block.commands.push(Command::RegexReplace),
 
+                "len" => block.commands.push(Command::Len),
+                "nth" => block.commands.push(Command::Nth),
+                "append" => block.commands.push(Command::Append),
+                "join" => block.commands.push(Command::Join),
+                "split" => block.commands.push(Command::Split),
+                "range" => block.commands.push(Command::Range),
+
+                "map" => block.commands.push(Command::NewMap),
+                "get" => block.commands.push(Command::Get),
+                "set" => block.commands.push(Command::Set),
+                "remove" => block.commands.push(Command::Remove),
+                "has" => block.commands.push(Command::Has),
+                "keys" => block.commands.push(Command::Keys),
+
+                "i32" => block.commands.push(Command::Convert(IntKind::I32.into())),
+                "u32" => block.commands.push(Command::Convert(IntKind::U32.into())),
+                "i64" => block.commands.push(Command::Convert(IntKind::I64.into())),
+                "u64" => block.commands.push(Command::Convert(IntKind::U64.into())),
+                "float" => block.commands.push(Command::Convert(Conversion::Float)),
+                "str" => block.commands.push(Command::Convert(Conversion::Str)),
+
+                "true" => block.commands.push(Command::PushBool(true)),
+                "false" => block.commands.push(Command::PushBool(false)),
                 "not" => block.commands.push(Command::Not),
+                "and" => parse_logical(l, blocks, &mut block, BinOp::And)?,
+                "or" => parse_logical(l, blocks, &mut block, BinOp::Or)?,
 
                 "dup" => block.commands.push(Command::Dup),
                 "pop" => block.commands.push(Command::Pop),
@@ -133,32 +338,25 @@ fn parse_block(
 
                 "exit" => block.commands.push(Command::Exit),
                 "debug" => block.commands.push(Command::Debug),
-                "if" => {
-                    let inner = parse_block(l, blocks)?;
-                    block.deps.extend(inner.deps.into_iter());
-                    block.commands.push(Command::If(inner.commands));
-                }
-                "while" => {
-                    let inner = parse_block(l, blocks)?;
-                    block.deps.extend(inner.deps.into_iter());
-                    block.commands.push(Command::While(inner.commands));
-                }
-                "call" => {
+                "call" | "load" => {
                     let id_token = expect_token(l, TokenKind::Identifier)?;
                     block.deps.push(id_token.source.clone());
                     block.commands.push(Command::Call(id_token.source));
                 }
-                "let" => {
-                    let id_token = expect_token(l, TokenKind::Identifier)?;
-                    block.commands.push(Command::Store(id_token.source));
-                }
                 _ => {
                     block.commands.push(Command::LoadVar(t.source));
                 }
             },
+            kind if kind.is_binop() => match BinOp::from_token_kind(kind) {
+                Some(op @ (BinOp::And | BinOp::Or)) => {
+                    parse_logical(l, blocks, &mut block, op)?;
+                }
+                Some(op) => block.commands.push(Command::BinOp(op)),
+                None => error!("{} Unsupported operator '{}'", t.loc, t.source),
+            },
             TokenKind::MacroCall => {
                 if let Some(m) = blocks.get(t.source.as_str()) {
-                    block.commands.extend(m.commands.clone().into_iter());
+                    block.commands.extend(m.commands.clone());
                 } else {
                     error!("Unexpected macro: {}", t.source)
                 }
@@ -170,13 +368,176 @@ fn parse_block(
     Ok(block)
 }
 
+/// Splits the source of a `FormatString` token into literal text and
+/// `{name}` placeholders; `{{` and `}}` stand for literal braces.
+fn parse_format(source: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = source.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                segments.push(Segment::Var(name));
+            }
+            _ => literal.push(ch),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    segments
+}
+
+/// Parses the blocks following `if`: `{ then }`, any number of
+/// `elif { cond } { body }` and an optional `else { body }`.
+fn parse_if(
+    l: &mut PeekableLexer<'_>,
+    blocks: &HashMap<String, Block>,
+    deps: &mut Vec<String>,
+) -> Result<Command, String> {
+    let mut parse_body = |l: &mut PeekableLexer<'_>| -> Result<Vec<Command>, String> {
+        let inner = parse_block(l, blocks)?;
+        deps.extend(inner.deps);
+        Ok(inner.commands)
+    };
+
+    let then = parse_body(l)?;
+    let mut elifs = Vec::new();
+    let mut otherwise = Vec::new();
+    loop {
+        let p = l.peek_token()?;
+        if p.kind != TokenKind::Keyword {
+            break;
+        }
+        match p.source.as_str() {
+            "elif" => {
+                l.next_token()?;
+                let cond = parse_body(l)?;
+                let body = parse_body(l)?;
+                elifs.push((cond, body));
+            }
+            "else" => {
+                l.next_token()?;
+                otherwise = parse_body(l)?;
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(Command::If {
+        then,
+        elifs,
+        otherwise,
+    })
+}
+
+/// `a b and` combines two bools eagerly, while `a and { ... }` only runs
+/// the block (which must push a bool) when `a` does not decide the result.
+fn parse_logical(
+    l: &mut PeekableLexer<'_>,
+    blocks: &HashMap<String, Block>,
+    block: &mut Block,
+    op: BinOp,
+) -> Result<(), String> {
+    if l.peek_token()?.kind != TokenKind::OpenBrace {
+        block.commands.push(Command::BinOp(op));
+        return Ok(());
+    }
+    let inner = parse_block(l, blocks)?;
+    block.deps.extend(inner.deps);
+    block
+        .commands
+        .push(Command::ShortCircuit(op, inner.commands));
+    Ok(())
+}
+
 use std::process::Command as SysCommand;
 use std::str;
+use std::time::Duration;
 
 #[derive(Debug, Default)]
 struct ExecutionEnv {
     stack: Stack,
     vars: HashMap<String, Value>,
+    shell: shell::Options,
+    /// Directory commands run in, `None` for the process' own
+    cwd: Option<PathBuf>,
+    /// Directories saved by `pushd`
+    dir_stack: Vec<Option<PathBuf>>,
+    /// Environment overrides for commands, `None` marks an unset variable
+    env_vars: BTreeMap<String, Option<String>>,
+    /// Background jobs started by `spawn`, keyed by their handle
+    jobs: HashMap<usize, shell::Running>,
+    next_job: usize,
+}
+
+impl Drop for ExecutionEnv {
+    /// Background jobs never outlive the directive that started them
+    fn drop(&mut self) {
+        self.kill_jobs();
+    }
+}
+
+impl ExecutionEnv {
+    fn kill_jobs(&mut self) {
+        for (_, job) in self.jobs.drain() {
+            let _ = job.kill();
+        }
+    }
+
+    /// Resolves `path` against the directory set by `cd`
+    fn resolve(&self, path: &str) -> PathBuf {
+        match &self.cwd {
+            Some(cwd) => cwd.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    /// Applies the working directory and environment to a command
+    fn prepare(&self, mut cmd: SysCommand) -> SysCommand {
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        for (key, value) in &self.env_vars {
+            match value {
+                Some(v) => cmd.env(key, v),
+                None => cmd.env_remove(key),
+            };
+        }
+        cmd
+    }
+
+    fn take_job(&mut self, handle: Value) -> Result<shell::Running, String> {
+        let Value::Job(id) = handle else {
+            error!("expected job but got {}", handle.type_name());
+        };
+        match self.jobs.remove(&id) {
+            Some(job) => Ok(job),
+            None => error!("job #{} was already waited for or killed", id),
+        }
+    }
+
+    fn change_dir(&mut self, dir: &str) -> Result<(), String> {
+        let path = self.resolve(dir);
+        if !path.is_dir() {
+            error!("cd: '{}' is not a directory", path.display());
+        }
+        self.cwd = Some(path);
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -189,12 +550,68 @@ struct Block {
 enum Command {
     /// Run a shell cmd, pop a string from the stack
     /// and push stdout back to the stack
-    /// TODO: Add a safety mode for Shell
     Shell,
+    /// Run a shell cmd, pop a string from the stack and push a Map with
+    /// its `stdout`, `stderr`, exit `code` and `duration` in seconds
+    Exec,
+    /// Pops a number of seconds and a shell cmd, runs it like `Shell` but
+    /// kills it once the time is up, pushing a message and false
+    Timeout,
+    /// Pops a shell cmd and an input string, runs the cmd like `Shell`
+    /// with the input written to its stdin
+    Feed,
+    /// Pops a shell cmd, starts it in the background and pushes a Job
+    Spawn,
+    /// Pops a Job, waits for it to exit and pushes the same Map as `Exec`
+    Wait,
+    /// Pops a Job and kills it along with its child processes
+    Kill,
+    /// Pops a Job and pushes "running" or "exited"
+    JobStatus,
+    /// Pops a list of strings and runs `args[0]` directly with the rest as
+    /// arguments, pushing the same Map as `Exec`
+    RunArgv,
+    /// Pops a directory and makes it the working directory of later
+    /// commands and file words
+    Cd,
+    /// Like `Cd`, but saves the current directory for `Popd`
+    Pushd,
+    /// Returns to the directory saved by the last `Pushd`
+    Popd,
+    /// Pops a value and a name, sets the environment variable for commands
+    SetEnv,
+    /// Pops a name and pushes the environment variable's value or Nil
+    GetEnv,
+    /// Pops a name and removes the environment variable for commands
+    UnsetEnv,
+    /// Pops a list of argv lists and runs them directly with each stdout
+    /// piped into the next stdin, pushing the Map of `Exec` for the last
+    /// stage plus `codes`, the exit code of every stage
+    Pipeline,
+    /// Pops a bool; while true, the lines logged before each shell command
+    /// are suppressed
+    Quiet,
+    /// Pops a bool; while true, shell output is shown live as well as
+    /// being captured for the stack
+    Stream,
     /// Push a String onto the stack
     PushStr(String),
+    /// Push a String built from literal text and variable values
+    PushFormat(Vec<Segment>),
     /// Push a i64 onto the stack
     PushInt(i64),
+    /// Push a i32 onto the stack
+    PushI32(i32),
+    /// Push a u32 onto the stack
+    PushU32(u32),
+    /// Push a u64 onto the stack
+    PushU64(u64),
+    /// Push a f64 onto the stack
+    PushFloat(f64),
+    /// Push a bool onto the stack
+    PushBool(bool),
+    /// Run the commands and push every value they left as a List
+    PushList(Vec<Command>),
     /// Pop a String from the stack and print it
     Echo,
     /// Duplicates the top value on the stack
@@ -205,18 +622,98 @@ enum Command {
     Swap,
     /// Concatenates top two strings and pushes the result
     Concat,
+    /// Pops a string and pushes it without leading and trailing whitespace
+    Trim,
+    /// Pops (string, from, to) and pushes the string with every `from`
+    /// replaced by `to`
+    Replace,
+    /// Pops a string and pushes it in upper case
+    Upper,
+    /// Pops a string and pushes it in lower case
+    Lower,
+    /// Pops a prefix and a string, pushes whether the string starts with it
+    StartsWith,
+    /// Pops a suffix and a string, pushes whether the string ends with it
+    EndsWith,
+    /// Pops a needle and a string or list, pushes whether it is contained
+    Contains,
+    /// Pops (string, start, count) and pushes `count` chars from `start`
+    Substr,
+    /// Pops a string and pushes the list of its lines
+    Lines,
+    /// Pops a pattern and a string, pushes whether the regex matches
+    Match,
+    /// Pops a pattern and a string, pushes the list of capture groups of the
+    /// first match (group 0 is the whole match), or an empty list
+    Captures,
+    /// Pops (string, pattern, replacement) and pushes the string with every
+    /// match replaced; `$1` or `${name}` in the replacement refer to groups
+    RegexReplace,
+    /// Pops a string, list or map and pushes its length
+    Len,
+    /// Pops an index and a list, pushes the element at that index
+    Nth,
+    /// Pops a value and a list, pushes the list with the value appended
+    Append,
+    /// Pops a separator and a list of strings, pushes them joined
+    Join,
+    /// Pops an end and a start integer, pushes the list `start..end`
+    Range,
+    /// Pops a separator and a string, pushes the list of parts.
+    /// An empty separator splits on whitespace
+    Split,
+    /// Push an empty Map onto the stack
+    NewMap,
+    /// Pops a key and a map, pushes the value stored under the key.
+    /// A dotted key such as `package.version` walks nested maps and lists
+    Get,
+    /// Pops a value, a key and a map, pushes the map with the key set
+    Set,
+    /// Pops a key and a map, pushes the map without the key
+    Remove,
+    /// Pops a key and a map, pushes whether the key is present
+    Has,
+    /// Pops a map and pushes the list of its keys
+    Keys,
+    /// Pops two values and pushes the result of the operator
+    BinOp(BinOp),
+    /// Pops a bool and runs the block only if it doesn't decide `and`/`or`
+    ShortCircuit(BinOp, Vec<Command>),
+    /// Pops a value and pushes it converted to another type
+    Convert(Conversion),
     /// Logical not
     Not,
     /// Reads a file from path on the stack, pushes file contents
     ReadFile,
     /// Writes the top of stack (string) to a file, path below it
     WriteFile,
-    /// Check if the top of stack is true and execute the block
-    If(Vec<Command>),
+    /// Pops a JSON string and pushes it as Map/List/scalar values
+    JsonParse,
+    /// Pops a value and pushes it serialised as pretty-printed JSON
+    JsonEmit,
+    /// Pops a TOML document string and pushes it as a Map
+    TomlParse,
+    /// Pops a bool and executes `then` if it is true. Otherwise each `elif`
+    /// condition is run and its body executed on the first true result,
+    /// falling back to `otherwise`
+    If {
+        then: Vec<Command>,
+        elifs: Vec<(Vec<Command>, Vec<Command>)>,
+        otherwise: Vec<Command>,
+    },
     /// While
     While(Vec<Command>),
+    /// Pops a list, map, string or integer and runs the block for each
+    /// element, key, line or number in `0..n`, storing it in the named variable
+    For(String, Vec<Command>),
     /// Call another block
     Call(String),
+    /// Leave the innermost `while`/`for` loop
+    Break,
+    /// Skip to the next iteration of the innermost `while`/`for` loop
+    Continue,
+    /// Leave the current directive
+    Return,
     /// Exit the program
     Exit,
     /// Prints the current stack
@@ -227,6 +724,204 @@ enum Command {
     LoadVar(String),
 }
 
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Var(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    NotEq,
+    Lt,
+    Gt,
+    And,
+    Or,
+}
+
+impl BinOp {
+    fn from_token_kind(kind: TokenKind) -> Option<Self> {
+        match kind {
+            TokenKind::Plus => Some(BinOp::Add),
+            TokenKind::Minus => Some(BinOp::Sub),
+            TokenKind::Asterisk => Some(BinOp::Mul),
+            TokenKind::Slash => Some(BinOp::Div),
+            TokenKind::Mod => Some(BinOp::Mod),
+            TokenKind::Eq => Some(BinOp::Eq),
+            TokenKind::NotEq => Some(BinOp::NotEq),
+            TokenKind::Lt => Some(BinOp::Lt),
+            TokenKind::Gt => Some(BinOp::Gt),
+            TokenKind::DoubleAmpersand => Some(BinOp::And),
+            TokenKind::DoublePipe => Some(BinOp::Or),
+            _ => None,
+        }
+    }
+
+    fn apply(self, a: Value, b: Value) -> Result<Value, String> {
+        let numeric = a.is_number() && b.is_number();
+        match self {
+            BinOp::Eq if !numeric => return Ok(Value::Bool(a == b)),
+            BinOp::NotEq if !numeric => return Ok(Value::Bool(a != b)),
+            BinOp::And | BinOp::Or => {
+                let a: bool = a.try_into()?;
+                let b: bool = b.try_into()?;
+                return Ok(Value::Bool(match self {
+                    BinOp::And => a && b,
+                    _ => a || b,
+                }));
+            }
+            _ => {}
+        }
+        match (a, b) {
+            (Value::Float(a), b) if numeric => Ok(self.apply_float(a, b.as_f64())),
+            (a, Value::Float(b)) if numeric => Ok(self.apply_float(a.as_f64(), b)),
+            (a, b) if numeric => {
+                let (ka, a) = a.as_int().unwrap();
+                let (kb, b) = b.as_int().unwrap();
+                let kind = match (ka, kb) {
+                    (ka, kb) if ka == kb => ka,
+                    (IntKind::I64, k) | (k, IntKind::I64) => k,
+                    _ => error!(
+                        "mismatched integer types for {:?}: {:?} and {:?}",
+                        self, ka, kb
+                    ),
+                };
+                self.apply_int(kind, a, b)
+            }
+            (Value::Str(a), Value::Str(b)) if matches!(self, BinOp::Lt | BinOp::Gt) => {
+                let ord = a.cmp(&b);
+                Ok(Value::Bool(match self {
+                    BinOp::Lt => ord.is_lt(),
+                    _ => ord.is_gt(),
+                }))
+            }
+            (a, b) => error!(
+                "invalid operands for {:?}: {} and {}",
+                self,
+                a.type_name(),
+                b.type_name()
+            ),
+        }
+    }
+
+    /// Operands are widened to i128 and the result is checked against the
+    /// range of `kind`, so overflow is reported for every integer type.
+    fn apply_int(self, kind: IntKind, a: i128, b: i128) -> Result<Value, String> {
+        let v = match self {
+            BinOp::Add => a.checked_add(b),
+            BinOp::Sub => a.checked_sub(b),
+            BinOp::Mul => a.checked_mul(b),
+            BinOp::Div => {
+                if b == 0 {
+                    error!("division by zero");
+                }
+                a.checked_div(b)
+            }
+            BinOp::Mod => {
+                if b == 0 {
+                    error!("division by zero");
+                }
+                a.checked_rem(b)
+            }
+            BinOp::Eq => return Ok(Value::Bool(a == b)),
+            BinOp::NotEq => return Ok(Value::Bool(a != b)),
+            BinOp::Lt => return Ok(Value::Bool(a < b)),
+            BinOp::Gt => return Ok(Value::Bool(a > b)),
+            BinOp::And | BinOp::Or => unreachable!(),
+        };
+        match v.and_then(|v| kind.make(v)) {
+            Some(v) => Ok(v),
+            None => error!("integer overflow in {:?} for {:?}", self, kind),
+        }
+    }
+
+    fn apply_float(self, a: f64, b: f64) -> Value {
+        match self {
+            BinOp::Add => Value::Float(a + b),
+            BinOp::Sub => Value::Float(a - b),
+            BinOp::Mul => Value::Float(a * b),
+            BinOp::Div => Value::Float(a / b),
+            BinOp::Mod => Value::Float(a % b),
+            BinOp::Eq => Value::Bool(a == b),
+            BinOp::NotEq => Value::Bool(a != b),
+            BinOp::Lt => Value::Bool(a < b),
+            BinOp::Gt => Value::Bool(a > b),
+            BinOp::And | BinOp::Or => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntKind {
+    I32,
+    U32,
+    I64,
+    U64,
+}
+
+impl IntKind {
+    fn make(self, v: i128) -> Option<Value> {
+        match self {
+            IntKind::I32 => i32::try_from(v).ok().map(Value::I32),
+            IntKind::U32 => u32::try_from(v).ok().map(Value::U32),
+            IntKind::I64 => i64::try_from(v).ok().map(Value::Int),
+            IntKind::U64 => u64::try_from(v).ok().map(Value::U64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Conversion {
+    Int(IntKind),
+    Float,
+    Str,
+}
+
+impl From<IntKind> for Conversion {
+    fn from(kind: IntKind) -> Self {
+        Conversion::Int(kind)
+    }
+}
+
+impl Conversion {
+    fn apply(self, v: Value) -> Result<Value, String> {
+        match self {
+            Conversion::Str => Ok(Value::Str(v.to_string())),
+            Conversion::Float => match v {
+                Value::Str(s) => match s.trim().parse() {
+                    Ok(f) => Ok(Value::Float(f)),
+                    Err(_) => error!("cannot convert '{}' to float", s),
+                },
+                v if v.is_number() => Ok(Value::Float(v.as_f64())),
+                v => error!("cannot convert {} to float", v.type_name()),
+            },
+            Conversion::Int(kind) => {
+                let n = match v {
+                    Value::Str(s) => match s.trim().parse::<i128>() {
+                        Ok(n) => n,
+                        Err(_) => error!("cannot convert '{}' to {:?}", s, kind),
+                    },
+                    Value::Float(f) if f.is_finite() => f.trunc() as i128,
+                    v => match v.as_int() {
+                        Some((_, n)) => n,
+                        None => error!("cannot convert {} to {:?}", v.type_name(), kind),
+                    },
+                };
+                match kind.make(n) {
+                    Some(v) => Ok(v),
+                    None => error!("{} does not fit in {:?}", n, kind),
+                }
+            }
+        }
+    }
+}
+
 fn resolve_dependencies(blocks: &HashMap<String, Block>, directive: &str) -> Result<(), String> {
     let mut seen = HashSet::new();
     let mut ordered = Vec::new();
@@ -262,8 +957,13 @@ fn resolve_dependencies(blocks: &HashMap<String, Block>, directive: &str) -> Res
     Ok(())
 }
 
-fn run_commands(directive: String, blocks: HashMap<String, Block>) -> Result<(), String> {
+fn run_commands(
+    directive: String,
+    blocks: HashMap<String, Block>,
+    opts: shell::Options,
+) -> Result<(), String> {
     let mut env = ExecutionEnv::default();
+    env.shell = opts;
 
     let Some(block) = blocks.get(&directive) else {
         error!("Directive '{}' not found.", directive);
@@ -271,17 +971,41 @@ fn run_commands(directive: String, blocks: HashMap<String, Block>) -> Result<(),
 
     resolve_dependencies(&blocks, &directive)?;
 
-    for cmd in &block.commands {
-        run_cmd(cmd, &mut env, &blocks)?;
+    match run_block(&block.commands, &mut env, &blocks)? {
+        Flow::Normal | Flow::Return => Ok(()),
+        flow => error!("{:?} outside of a loop in '{}'", flow, directive),
     }
-    Ok(())
+}
+
+/// How control leaves a command: `break`, `continue` and `return` unwind
+/// through `run_block` until a loop or directive call handles them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return,
+}
+
+fn run_block(
+    cmds: &[Command],
+    env: &mut ExecutionEnv,
+    blocks: &HashMap<String, Block>,
+) -> Result<Flow, String> {
+    for cmd in cmds {
+        let flow = run_cmd(cmd, env, blocks)?;
+        if flow != Flow::Normal {
+            return Ok(flow);
+        }
+    }
+    Ok(Flow::Normal)
 }
 
 fn run_cmd(
     cmd: &Command,
     env: &mut ExecutionEnv,
     blocks: &HashMap<String, Block>,
-) -> Result<(), String> {
+) -> Result<Flow, String> {
     match cmd {
         Command::Debug => {
             println!("DEBUG {:?}", env.stack.inner)
@@ -291,10 +1015,57 @@ fn run_cmd(
             env.stack.push(Value::Str(s.clone()));
         }
 
+        Command::PushFormat(segments) => {
+            let mut s = String::new();
+            for segment in segments {
+                match segment {
+                    Segment::Literal(lit) => s.push_str(lit),
+                    Segment::Var(var) => match env.vars.get(var) {
+                        Some(v) => s.push_str(&v.to_string()),
+                        None => error!("undefined variable '{}' in string", var),
+                    },
+                }
+            }
+            env.stack.push(Value::Str(s));
+        }
+
         Command::PushInt(s) => {
             env.stack.push(Value::Int(*s));
         }
 
+        Command::PushI32(i) => {
+            env.stack.push(Value::I32(*i));
+        }
+
+        Command::PushU32(i) => {
+            env.stack.push(Value::U32(*i));
+        }
+
+        Command::PushU64(i) => {
+            env.stack.push(Value::U64(*i));
+        }
+
+        Command::PushFloat(f) => {
+            env.stack.push(Value::Float(*f));
+        }
+
+        Command::PushBool(b) => {
+            env.stack.push(Value::Bool(*b));
+        }
+
+        Command::PushList(cmds) => {
+            let base = env.stack.len();
+            let flow = run_block(cmds, env, blocks)?;
+            if flow != Flow::Normal {
+                return Ok(flow);
+            }
+            if env.stack.len() < base {
+                error!("list literal consumed values from the outer stack");
+            }
+            let items = env.stack.drain_from(base);
+            env.stack.push(Value::List(items));
+        }
+
         Command::Echo => {
             let msg: String = env.stack.pop()?.try_into()?;
             println!("{msg}")
@@ -317,9 +1088,242 @@ fn run_cmd(
         }
 
         Command::Concat => {
-            let b: String = env.stack.pop()?.try_into()?;
-            let a: String = env.stack.pop()?.try_into()?;
-            env.stack.push(Value::Str(a + b.as_str()));
+            let b = env.stack.pop()?;
+            let a = env.stack.pop()?;
+            env.stack.push(Value::Str(format!("{a}{b}")));
+        }
+
+        Command::Trim => {
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Str(s.trim().into()));
+        }
+
+        Command::Replace => {
+            let to: String = env.stack.pop()?.try_into()?;
+            let from: String = env.stack.pop()?.try_into()?;
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Str(s.replace(&from, &to)));
+        }
+
+        Command::Upper => {
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Str(s.to_uppercase()));
+        }
+
+        Command::Lower => {
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Str(s.to_lowercase()));
+        }
+
+        Command::StartsWith => {
+            let prefix: String = env.stack.pop()?.try_into()?;
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Bool(s.starts_with(&prefix)));
+        }
+
+        Command::EndsWith => {
+            let suffix: String = env.stack.pop()?.try_into()?;
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Bool(s.ends_with(&suffix)));
+        }
+
+        Command::Contains => {
+            let needle = env.stack.pop()?;
+            let found = match env.stack.pop()? {
+                Value::List(items) => items.contains(&needle),
+                v => {
+                    let s: String = v.try_into()?;
+                    let needle: String = needle.try_into()?;
+                    s.contains(&needle)
+                }
+            };
+            env.stack.push(Value::Bool(found));
+        }
+
+        Command::Substr => {
+            let count: i64 = env.stack.pop()?.try_into()?;
+            let start: i64 = env.stack.pop()?.try_into()?;
+            let s: String = env.stack.pop()?.try_into()?;
+            if start < 0 || count < 0 {
+                error!("substr start and count must not be negative");
+            }
+            let sub = s.chars().skip(start as usize).take(count as usize);
+            env.stack.push(Value::Str(sub.collect()));
+        }
+
+        Command::Lines => {
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::List(
+                s.lines().map(|l| Value::Str(l.into())).collect(),
+            ));
+        }
+
+        Command::Match => {
+            let re = compile_regex(env.stack.pop()?)?;
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Bool(re.is_match(&s)));
+        }
+
+        Command::Captures => {
+            let re = compile_regex(env.stack.pop()?)?;
+            let s: String = env.stack.pop()?.try_into()?;
+            let groups = match re.captures(&s) {
+                Some(caps) => caps
+                    .iter()
+                    .map(|m| m.map_or(Value::Nil, |m| Value::Str(m.as_str().into())))
+                    .collect(),
+                None => Vec::new(),
+            };
+            env.stack.push(Value::List(groups));
+        }
+
+        Command::RegexReplace => {
+            let replacement: String = env.stack.pop()?.try_into()?;
+            let re = compile_regex(env.stack.pop()?)?;
+            let s: String = env.stack.pop()?.try_into()?;
+            env.stack
+                .push(Value::Str(re.replace_all(&s, replacement.as_str()).into()));
+        }
+
+        Command::Len => {
+            let len = match env.stack.pop()? {
+                Value::Str(s) => s.chars().count(),
+                Value::Map(map) => map.len(),
+                v => Vec::<Value>::try_from(v)?.len(),
+            };
+            env.stack.push(Value::Int(len as i64));
+        }
+
+        Command::Nth => {
+            let index: i64 = env.stack.pop()?.try_into()?;
+            let list: Vec<Value> = env.stack.pop()?.try_into()?;
+            let len = list.len();
+            match usize::try_from(index)
+                .ok()
+                .and_then(|i| list.into_iter().nth(i))
+            {
+                Some(v) => env.stack.push(v),
+                None => error!(
+                    "nth index {} out of bounds for list of length {}",
+                    index, len
+                ),
+            }
+        }
+
+        Command::Append => {
+            let v = env.stack.pop()?;
+            let mut list: Vec<Value> = env.stack.pop()?.try_into()?;
+            list.push(v);
+            env.stack.push(Value::List(list));
+        }
+
+        Command::Join => {
+            let sep: String = env.stack.pop()?.try_into()?;
+            let list: Vec<Value> = env.stack.pop()?.try_into()?;
+            let parts = list
+                .into_iter()
+                .map(String::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            env.stack.push(Value::Str(parts.join(&sep)));
+        }
+
+        Command::Range => {
+            let end: i64 = env.stack.pop()?.try_into()?;
+            let start: i64 = env.stack.pop()?.try_into()?;
+            let len = end.saturating_sub(start);
+            if len > MAX_RANGE {
+                error!(
+                    "range {start}..{end} has more than {MAX_RANGE} items, use `n for` to count lazily"
+                );
+            }
+            env.stack
+                .push(Value::List((start..end).map(Value::Int).collect()));
+        }
+
+        Command::Split => {
+            let sep: String = env.stack.pop()?.try_into()?;
+            let s: String = env.stack.pop()?.try_into()?;
+            let parts = if sep.is_empty() {
+                s.split_whitespace().map(|p| Value::Str(p.into())).collect()
+            } else {
+                s.split(sep.as_str())
+                    .map(|p| Value::Str(p.into()))
+                    .collect()
+            };
+            env.stack.push(Value::List(parts));
+        }
+
+        Command::NewMap => {
+            env.stack.push(Value::Map(BTreeMap::new()));
+        }
+
+        Command::Get => {
+            let key: String = env.stack.pop()?.try_into()?;
+            let mut map: BTreeMap<String, Value> = env.stack.pop()?.try_into()?;
+            let found = match map.remove(&key) {
+                Some(v) => Some(v),
+                None => Value::Map(map).lookup(&key),
+            };
+            match found {
+                Some(v) => env.stack.push(v),
+                None => error!("get key '{}' not found", key),
+            }
+        }
+
+        Command::Set => {
+            let v = env.stack.pop()?;
+            let key: String = env.stack.pop()?.try_into()?;
+            let mut map: BTreeMap<String, Value> = env.stack.pop()?.try_into()?;
+            map.insert(key, v);
+            env.stack.push(Value::Map(map));
+        }
+
+        Command::Remove => {
+            let key: String = env.stack.pop()?.try_into()?;
+            let mut map: BTreeMap<String, Value> = env.stack.pop()?.try_into()?;
+            map.remove(&key);
+            env.stack.push(Value::Map(map));
+        }
+
+        Command::Has => {
+            let key: String = env.stack.pop()?.try_into()?;
+            let map: BTreeMap<String, Value> = env.stack.pop()?.try_into()?;
+            env.stack.push(Value::Bool(map.contains_key(&key)));
+        }
+
+        Command::Keys => {
+            let map: BTreeMap<String, Value> = env.stack.pop()?.try_into()?;
+            env.stack
+                .push(Value::List(map.into_keys().map(Value::Str).collect()));
+        }
+
+        Command::BinOp(op) => {
+            let b = env.stack.pop()?;
+            let a = env.stack.pop()?;
+            env.stack.push(op.apply(a, b)?);
+        }
+
+        Command::ShortCircuit(op, cmds) => {
+            let a: bool = env.stack.pop()?.try_into()?;
+            let decided = match op {
+                BinOp::And => !a,
+                _ => a,
+            };
+            if decided {
+                env.stack.push(Value::Bool(a));
+            } else {
+                let flow = run_block(cmds, env, blocks)?;
+                if flow != Flow::Normal {
+                    return Ok(flow);
+                }
+                let b: bool = env.stack.pop()?.try_into()?;
+                env.stack.push(Value::Bool(b));
+            }
+        }
+
+        Command::Convert(conv) => {
+            let v = env.stack.pop()?;
+            env.stack.push(conv.apply(v)?);
         }
 
         Command::Not => {
@@ -329,7 +1333,7 @@ fn run_cmd(
 
         Command::ReadFile => {
             let path: String = env.stack.pop()?.try_into()?;
-            match fs::read_to_string(&path) {
+            match fs::read_to_string(env.resolve(&path)) {
                 Ok(content) => env.stack.push(Value::Str(content)),
                 Err(e) => error!("readfile error: {}", e),
             }
@@ -338,25 +1342,54 @@ fn run_cmd(
         Command::WriteFile => {
             let content: String = env.stack.pop()?.try_into()?;
             let path: String = env.stack.pop()?.try_into()?;
-            match fs::write(&path, content) {
+            match fs::write(env.resolve(&path), content) {
                 Ok(_) => {}
                 Err(e) => error!("writefile error: {}", e),
             }
         }
 
+        Command::JsonParse => {
+            let source: String = env.stack.pop()?.try_into()?;
+            env.stack.push(json::parse(&source)?);
+        }
+
+        Command::JsonEmit => {
+            let v = env.stack.pop()?;
+            env.stack.push(Value::Str(json::emit(&v)?));
+        }
+
+        Command::TomlParse => {
+            let source: String = env.stack.pop()?.try_into()?;
+            env.stack.push(toml::parse(&source)?);
+        }
+
         Command::Exit => {
             let code: i64 = env.stack.pop()?.try_into()?;
+            // `process::exit` skips `Drop`, so jobs are killed here
+            env.kill_jobs();
             process::exit(code as i32);
         }
 
-        Command::If(cmds) => {
+        Command::If {
+            then,
+            elifs,
+            otherwise,
+        } => {
             let cond: bool = env.stack.pop()?.try_into()?;
 
-            if cond {
-                for cmd in cmds {
-                    run_cmd(cmd, env, blocks)?;
+            let mut branch = cond.then_some(then);
+            for (cond_cmds, body) in elifs {
+                if branch.is_some() {
+                    break;
                 }
+                let flow = run_block(cond_cmds, env, blocks)?;
+                if flow != Flow::Normal {
+                    return Ok(flow);
+                }
+                let cond: bool = env.stack.pop()?.try_into()?;
+                branch = cond.then_some(body);
             }
+            return run_block(branch.unwrap_or(otherwise), env, blocks);
         }
 
         Command::While(cmds) => loop {
@@ -365,20 +1398,56 @@ fn run_cmd(
             if !cond {
                 break;
             }
-            for cmd in cmds {
-                run_cmd(cmd, env, blocks)?;
+            match run_block(cmds, env, blocks)? {
+                Flow::Break => break,
+                Flow::Return => return Ok(Flow::Return),
+                Flow::Normal | Flow::Continue => {}
             }
         },
 
+        Command::For(var, cmds) => {
+            // An integer bound is iterated lazily so `n` can be huge when the
+            // body breaks early
+            let items: Box<dyn Iterator<Item = Value>> = match env.stack.pop()? {
+                Value::List(items) => Box::new(items.into_iter()),
+                Value::Map(map) => Box::new(map.into_keys().map(Value::Str)),
+                Value::Str(s) => Box::new(
+                    s.lines()
+                        .map(|l| Value::Str(l.into()))
+                        .collect::<Vec<_>>()
+                        .into_iter(),
+                ),
+                v => {
+                    let n: i64 = v.try_into()?;
+                    Box::new((0..n).map(Value::Int))
+                }
+            };
+            for item in items {
+                env.vars.insert(var.clone(), item);
+                match run_block(cmds, env, blocks)? {
+                    Flow::Break => break,
+                    Flow::Return => return Ok(Flow::Return),
+                    Flow::Normal | Flow::Continue => {}
+                }
+            }
+        }
+
         Command::Call(block_name) => {
             let Some(b) = blocks.get(block_name) else {
                 error!("call block '{}' not found", block_name);
             };
-            for cmd in &b.commands {
-                run_cmd(cmd, env, blocks)?;
+            match run_block(&b.commands, env, blocks)? {
+                Flow::Normal | Flow::Return => {}
+                flow => error!("{:?} outside of a loop in '{}'", flow, block_name),
             }
         }
 
+        Command::Break => return Ok(Flow::Break),
+
+        Command::Continue => return Ok(Flow::Continue),
+
+        Command::Return => return Ok(Flow::Return),
+
         Command::Store(var) => {
             let v = env.stack.pop()?;
             env.vars.insert(var.clone(), v);
@@ -391,36 +1460,209 @@ fn run_cmd(
 
         Command::Shell => {
             let cmd: String = env.stack.pop()?.try_into()?;
-            match SysCommand::new("sh").arg("-c").arg(&cmd).output() {
-                Ok(output) => {
-                    if output.status.success() {
-                        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                        println!("Shell -> '{cmd}'");
-                        env.stack.push(Value::Str(stdout));
-                        env.stack.push(Value::Bool(true));
-                    } else {
-                        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-                        println!("Shell -> '{cmd}'");
-                        env.stack.push(Value::Str(stderr));
-                        env.stack.push(Value::Bool(false));
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to run shell: {}", e);
+            env.shell.check(&cmd)?;
+            env.shell.log(format_args!("Shell -> '{cmd}'"));
+            let output = shell::run(env.prepare(env.shell.command(&cmd)), &env.shell)?;
+            push_shell_result(env, output);
+        }
+
+        Command::Timeout => {
+            let secs = env.stack.pop()?;
+            let limit = match Duration::try_from_secs_f64(secs.as_f64()) {
+                Ok(limit) if secs.is_number() => limit,
+                _ => error!("timeout expects a positive number of seconds, got {}", secs),
+            };
+            let cmd: String = env.stack.pop()?.try_into()?;
+            env.shell.check(&cmd)?;
+            env.shell.log(format_args!("Shell -> '{cmd}'"));
+            let cmd = env.prepare(env.shell.command(&cmd));
+            match shell::Running::spawn_group(cmd, &env.shell)?.wait_timeout(limit)? {
+                Some(output) => push_shell_result(env, output),
+                None => {
+                    env.stack
+                        .push(Value::Str(format!("timed out after {secs}s")));
+                    env.stack.push(Value::Bool(false));
                 }
             }
         }
+
+        Command::Feed => {
+            let cmd: String = env.stack.pop()?.try_into()?;
+            let input = env.stack.pop()?.to_string();
+            env.shell.check(&cmd)?;
+            env.shell.log(format_args!("Shell -> '{cmd}'"));
+            let cmd = env.prepare(env.shell.command(&cmd));
+            let output = shell::Running::spawn(cmd, &env.shell, Some(input))?.wait()?;
+            push_shell_result(env, output);
+        }
+
+        Command::Spawn => {
+            let cmd: String = env.stack.pop()?.try_into()?;
+            env.shell.check(&cmd)?;
+            env.shell.log(format_args!("Spawn -> '{cmd}'"));
+            let cmd = env.prepare(env.shell.command(&cmd));
+            let job = shell::Running::spawn_group(cmd, &env.shell)?;
+            let id = env.next_job;
+            env.next_job += 1;
+            env.jobs.insert(id, job);
+            env.stack.push(Value::Job(id));
+        }
+
+        Command::Wait => {
+            let handle = env.stack.pop()?;
+            let output = env.take_job(handle)?.wait()?;
+            env.stack.push(output.into_value());
+        }
+
+        Command::Kill => {
+            let handle = env.stack.pop()?;
+            env.take_job(handle)?.kill()?;
+        }
+
+        Command::JobStatus => {
+            let Value::Job(id) = env.stack.pop()? else {
+                error!("job_status expects a job");
+            };
+            let Some(job) = env.jobs.get_mut(&id) else {
+                error!("job #{} was already waited for or killed", id);
+            };
+            let status = if job.is_running()? {
+                "running"
+            } else {
+                "exited"
+            };
+            env.stack.push(Value::Str(status.into()));
+        }
+
+        Command::RunArgv => {
+            let args = pop_string_list(env)?;
+            env.shell.check_argv(&args.join(" "))?;
+            env.shell.log(format_args!("Run -> {args:?}"));
+            let output = shell::run(env.prepare(shell::argv_command(&args)?), &env.shell)?;
+            env.stack.push(output.into_value());
+        }
+
+        Command::Cd => {
+            let dir: String = env.stack.pop()?.try_into()?;
+            env.change_dir(&dir)?;
+        }
+
+        Command::Pushd => {
+            let dir: String = env.stack.pop()?.try_into()?;
+            let prev = env.cwd.clone();
+            env.change_dir(&dir)?;
+            env.dir_stack.push(prev);
+        }
+
+        Command::Popd => match env.dir_stack.pop() {
+            Some(dir) => env.cwd = dir,
+            None => error!("popd with an empty directory stack"),
+        },
+
+        Command::SetEnv => {
+            let value = env.stack.pop()?.to_string();
+            let name: String = env.stack.pop()?.try_into()?;
+            env.shell.check_env(&name)?;
+            env.env_vars.insert(name, Some(value));
+        }
+
+        Command::GetEnv => {
+            let name: String = env.stack.pop()?.try_into()?;
+            let value = match env.env_vars.get(&name) {
+                Some(v) => v.clone(),
+                None => std::env::var(&name).ok(),
+            };
+            env.stack.push(value.map_or(Value::Nil, Value::Str));
+        }
+
+        Command::UnsetEnv => {
+            let name: String = env.stack.pop()?.try_into()?;
+            env.shell.check_env(&name)?;
+            env.env_vars.insert(name, None);
+        }
+
+        Command::Pipeline => {
+            let list: Vec<Value> = env.stack.pop()?.try_into()?;
+            let mut stages = Vec::new();
+            let mut display = Vec::new();
+            for stage in list {
+                let args = Vec::<Value>::try_from(stage)?
+                    .into_iter()
+                    .map(String::try_from)
+                    .collect::<Result<Vec<_>, _>>()?;
+                env.shell.check_argv(&args.join(" "))?;
+                stages.push(env.prepare(shell::argv_command(&args)?));
+                display.push(args);
+            }
+            env.shell.log(format_args!("Pipeline -> {display:?}"));
+            let (output, codes) = shell::run_pipeline(stages, &env.shell)?;
+            let mut record = output.into_value();
+            if let Value::Map(map) = &mut record {
+                let codes = codes
+                    .into_iter()
+                    .map(|c| c.map_or(Value::Nil, |c| Value::Int(c as i64)))
+                    .collect();
+                map.insert("codes".into(), Value::List(codes));
+            }
+            env.stack.push(record);
+        }
+
+        Command::Quiet => {
+            env.shell.quiet = env.stack.pop()?.try_into()?;
+        }
+
+        Command::Stream => {
+            env.shell.stream = env.stack.pop()?.try_into()?;
+        }
+
+        Command::Exec => {
+            let cmd: String = env.stack.pop()?.try_into()?;
+            env.shell.check(&cmd)?;
+            env.shell.log(format_args!("Shell -> '{cmd}'"));
+            let output = shell::run(env.prepare(env.shell.command(&cmd)), &env.shell)?;
+            env.stack.push(output.into_value());
+        }
+    }
+    Ok(Flow::Normal)
+}
+
+fn pop_string_list(env: &mut ExecutionEnv) -> Result<Vec<String>, String> {
+    let list: Vec<Value> = env.stack.pop()?.try_into()?;
+    list.into_iter().map(String::try_from).collect()
+}
+
+/// Pushes what `shell` reports: the trimmed stdout and `true` on success,
+/// or the trimmed stderr and `false` on failure
+fn push_shell_result(env: &mut ExecutionEnv, output: shell::Output) {
+    if output.success() {
+        env.stack.push(Value::Str(output.stdout.trim().to_string()));
+        env.stack.push(Value::Bool(true));
+    } else {
+        env.stack.push(Value::Str(output.stderr.trim().to_string()));
+        env.stack.push(Value::Bool(false));
     }
-    Ok(())
 }
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+fn compile_regex(pattern: Value) -> Result<Regex, String> {
+    let pattern: String = pattern.try_into()?;
+    Regex::new(&pattern).map_err(|err| format!("invalid regex '{pattern}': {err}"))
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 pub enum Value {
     #[default]
     Nil,
     Str(String),
     Int(i64),
+    I32(i32),
+    U32(u32),
+    U64(u64),
+    Float(f64),
     Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    /// Handle of a background job started by `spawn`
+    Job(usize),
 }
 
 impl Value {
@@ -429,7 +1671,80 @@ impl Value {
             Value::Nil => "Nil",
             Value::Str(_) => "Str",
             Value::Int(_) => "Int",
+            Value::I32(_) => "I32",
+            Value::U32(_) => "U32",
+            Value::U64(_) => "U64",
+            Value::Float(_) => "Float",
             Value::Bool(_) => "Bool",
+            Value::List(_) => "List",
+            Value::Map(_) => "Map",
+            Value::Job(_) => "Job",
+        }
+    }
+
+    /// Follows a dotted path through nested maps and list indices
+    fn lookup(self, path: &str) -> Option<Value> {
+        path.split('.').try_fold(self, |v, part| match v {
+            Value::Map(mut map) => map.remove(part),
+            Value::List(items) => items.into_iter().nth(part.parse().ok()?),
+            _ => None,
+        })
+    }
+
+    fn as_int(&self) -> Option<(IntKind, i128)> {
+        match self {
+            Value::Int(i) => Some((IntKind::I64, *i as i128)),
+            Value::I32(i) => Some((IntKind::I32, *i as i128)),
+            Value::U32(i) => Some((IntKind::U32, *i as i128)),
+            Value::U64(i) => Some((IntKind::U64, *i as i128)),
+            _ => None,
+        }
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(self, Value::Float(_)) || self.as_int().is_some()
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Value::Float(f) => *f,
+            v => v.as_int().map_or(f64::NAN, |(_, i)| i as f64),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Int(i) => write!(f, "{i}"),
+            Value::I32(i) => write!(f, "{i}"),
+            Value::U32(i) => write!(f, "{i}"),
+            Value::U64(i) => write!(f, "{i}"),
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Value::Job(id) => write!(f, "job#{id}"),
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{k}: {v}")?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -447,9 +1762,9 @@ impl TryFrom<Value> for String {
 impl TryFrom<Value> for i64 {
     type Error = String;
     fn try_from(value: Value) -> Result<Self, Self::Error> {
-        match value {
-            Value::Int(s) => Ok(s),
-            v => Err(format!("expected int but got {}", v.type_name())),
+        match value.as_int() {
+            Some((_, i)) => i64::try_from(i).map_err(|_| format!("{i} does not fit in an int")),
+            None => Err(format!("expected int but got {}", value.type_name())),
         }
     }
 }
@@ -464,6 +1779,26 @@ impl TryFrom<Value> for bool {
     }
 }
 
+impl TryFrom<Value> for Vec<Value> {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::List(s) => Ok(s),
+            v => Err(format!("expected list but got {}", v.type_name())),
+        }
+    }
+}
+
+impl TryFrom<Value> for BTreeMap<String, Value> {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Map(s) => Ok(s),
+            v => Err(format!("expected map but got {}", v.type_name())),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Stack {
     inner: Vec<Value>,
@@ -484,4 +1819,16 @@ impl Stack {
     pub fn push(&mut self, v: Value) {
         self.inner.push(v);
     }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn drain_from(&mut self, base: usize) -> Vec<Value> {
+        self.inner.drain(base..).collect()
+    }
 }
@@ -0,0 +1,477 @@
+use crate::Value;
+use clap::ValueEnum;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command as SysCommand, Stdio};
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How commands are vetted before they are started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Run every command
+    #[default]
+    Allow,
+    /// Ask on the terminal before each command
+    Prompt,
+    /// Refuse to run any command
+    Deny,
+    /// Only run commands fully matching one of the allowed patterns
+    Allowlist,
+}
+
+/// Settings shared by every word that starts a process
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Tee child stdout/stderr to the terminal while capturing it
+    pub stream: bool,
+    /// Suppress the `Shell -> '...'` lines logged before each command
+    pub quiet: bool,
+    pub mode: Mode,
+    /// Patterns consulted in `Mode::Allowlist`, anchored at both ends
+    pub allow: Vec<Regex>,
+    /// Program and leading arguments the command string is appended to,
+    /// `sh -c` when empty
+    pub interpreter: Vec<String>,
+}
+
+impl Options {
+    /// Logs which command is about to run. Logs go to stderr so stdout only
+    /// carries what the script itself prints
+    pub fn log(&self, msg: fmt::Arguments<'_>) {
+        if !self.quiet {
+            eprintln!("{msg}");
+        }
+    }
+
+    /// Builds the `<interpreter> <cmd>` invocation used by the shell words
+    pub fn command(&self, cmd: &str) -> SysCommand {
+        let mut c = match self.interpreter.split_first() {
+            Some((program, args)) => {
+                let mut c = SysCommand::new(program);
+                c.args(args);
+                c
+            }
+            None => {
+                let mut c = SysCommand::new("sh");
+                c.arg("-c");
+                c
+            }
+        };
+        c.arg(cmd);
+        c
+    }
+
+    pub fn allow_pattern(&mut self, pattern: &str) -> Result<(), String> {
+        let re = Regex::new(&format!("^(?:{pattern})$"))
+            .map_err(|err| format!("invalid shell allow pattern '{pattern}': {err}"))?;
+        self.allow.push(re);
+        Ok(())
+    }
+
+    /// Checks a command string for the interpreter against the safety mode
+    /// before it is run. In allowlist mode strings with shell metacharacters
+    /// are refused, since a pattern such as `echo .*` would otherwise also
+    /// match `echo ok; rm -rf ~`.
+    pub fn check(&self, cmd: &str) -> Result<(), String> {
+        let chains = cmd.contains(SHELL_META) || cmd.contains("$(");
+        if matches!(self.mode, Mode::Allowlist) && chains {
+            return Err(format!(
+                "'{cmd}' contains shell metacharacters, refused in allowlist mode"
+            ));
+        }
+        self.check_argv(cmd)
+    }
+
+    /// Refuses to change variables that decide which program actually runs
+    /// (`PATH`, `LD_*`, `BASH_ENV`, ...) unless every command is allowed,
+    /// as they would let an allowed command line start something else
+    pub fn check_env(&self, name: &str) -> Result<(), String> {
+        let hijacks =
+            HIJACK_VARS.contains(&name) || name.starts_with("LD_") || name.starts_with("DYLD_");
+        if self.mode != Mode::Allow && hijacks {
+            return Err(format!(
+                "changing '{name}' is only allowed in `allow` shell mode"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks an argv command, given as its words joined by spaces. No shell
+    /// parses it, so metacharacters in the arguments are harmless.
+    pub fn check_argv(&self, cmd: &str) -> Result<(), String> {
+        match self.mode {
+            Mode::Allow => Ok(()),
+            Mode::Deny => Err(format!("shell is disabled, refusing to run '{cmd}'")),
+            Mode::Allowlist => {
+                if self.allow.iter().any(|re| re.is_match(cmd)) {
+                    Ok(())
+                } else {
+                    Err(format!("'{cmd}' is not in the shell allowlist"))
+                }
+            }
+            Mode::Prompt => {
+                eprint!("Run '{cmd}'? [y/N] ");
+                let _ = io::stderr().flush();
+                let mut answer = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut answer)
+                    .map_err(|e| format!("Failed to read answer: {}", e))?;
+                match answer.trim() {
+                    "y" | "Y" | "yes" => Ok(()),
+                    _ => Err(format!("refused to run '{cmd}'")),
+                }
+            }
+        }
+    }
+}
+
+/// Characters that let one shell command string run or redirect to others
+const SHELL_META: &[char] = &[';', '&', '|', '`', '<', '>', '\n'];
+
+/// Variables that change which program a command line runs or what a shell
+/// executes first
+const HIJACK_VARS: &[&str] = &["PATH", "ENV", "BASH_ENV", "IFS", "SHELLOPTS", "BASHOPTS"];
+
+/// Everything a finished child process left behind
+#[derive(Debug)]
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    /// `None` when the process was terminated by a signal
+    pub code: Option<i32>,
+    pub duration: Duration,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Converts into the record pushed by `exec`:
+    /// `{ stdout, stderr, code, duration }`
+    pub fn into_value(self) -> Value {
+        let mut record = BTreeMap::new();
+        record.insert("stdout".into(), Value::Str(self.stdout));
+        record.insert("stderr".into(), Value::Str(self.stderr));
+        record.insert(
+            "code".into(),
+            self.code.map_or(Value::Nil, |c| Value::Int(c as i64)),
+        );
+        record.insert("duration".into(), Value::Float(self.duration.as_secs_f64()));
+        Value::Map(record)
+    }
+}
+
+/// Splits an interpreter setting such as `bash`, `bash -o pipefail` or
+/// `node -e` into its argv. `-c`, which is how sh, bash, zsh and fish all
+/// take a command string, is appended to a bare program name and to a known
+/// shell not already ending in `-c`; anything else is used as written
+pub fn parse_interpreter(spec: &str) -> Result<Vec<String>, String> {
+    let mut argv: Vec<String> = spec.split_whitespace().map(String::from).collect();
+    let (Some(program), Some(last)) = (argv.first(), argv.last()) else {
+        return Err("interpreter must not be empty".into());
+    };
+    let name = program.rsplit('/').next().unwrap_or(program);
+    let is_shell = SHELLS.contains(&name) && last != "-c";
+    if argv.len() == 1 || is_shell {
+        argv.push("-c".into());
+    }
+    Ok(argv)
+}
+
+/// Interpreters that take a command string after `-c`
+const SHELLS: &[&str] = &["sh", "bash", "dash", "ksh", "zsh", "fish"];
+
+/// Builds a direct invocation of `args[0]` with the remaining arguments,
+/// without going through a shell
+pub fn argv_command(args: &[String]) -> Result<SysCommand, String> {
+    let Some((program, rest)) = args.split_first() else {
+        return Err("expected a program name in the argument list".into());
+    };
+    let mut c = SysCommand::new(program);
+    c.args(rest);
+    Ok(c)
+}
+
+/// A spawned child whose stdout/stderr are drained by background threads
+#[derive(Debug)]
+pub struct Running {
+    child: Child,
+    stdout: JoinHandle<Vec<u8>>,
+    stderr: JoinHandle<Vec<u8>>,
+    start: Instant,
+    /// Slot in `GROUPS` when the child leads its own process group
+    group: Option<usize>,
+}
+
+impl Running {
+    /// Starts `cmd`. With `input`, the child's stdin is a pipe that a
+    /// background thread fills with it and then closes
+    pub fn spawn(
+        mut cmd: SysCommand,
+        opts: &Options,
+        input: Option<String>,
+    ) -> Result<Self, String> {
+        let start = Instant::now();
+        // Without input the child gets an empty stdin, as with `output()`,
+        // so it never reads or blocks on proj's own stdin
+        cmd.stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run '{}': {}", cmd.get_program().display(), e))?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // The child may exit without reading everything; a broken pipe
+            // is not an error for us
+            thread::spawn(move || stdin.write_all(input.as_bytes()));
+        }
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        let stream = opts.stream;
+        Ok(Self {
+            child,
+            stdout: thread::spawn(move || capture(stdout, stream.then(io::stdout))),
+            stderr: thread::spawn(move || capture(stderr, stream.then(io::stderr))),
+            start,
+            group: None,
+        })
+    }
+
+    /// Starts `cmd` in a new process group it leads, so `kill_group` can
+    /// take down everything it started. Terminal signals such as Ctrl-C no
+    /// longer reach the group, so it is killed from proj's own handler
+    pub fn spawn_group(mut cmd: SysCommand, opts: &Options) -> Result<Self, String> {
+        cmd.process_group(0);
+        let mut running = Self::spawn(cmd, opts, None)?;
+        running.group = track_group(running.child.id() as libc::pid_t);
+        Ok(running)
+    }
+
+    pub fn wait(mut self) -> Result<Output, String> {
+        let status = self
+            .child
+            .wait()
+            .map_err(|e| format!("Failed to wait for shell: {}", e))?;
+        if let Some(slot) = self.group {
+            GROUPS[slot].store(0, Ordering::SeqCst);
+        }
+        Ok(Output {
+            stdout: String::from_utf8_lossy(&self.stdout.join().unwrap_or_default()).into_owned(),
+            stderr: String::from_utf8_lossy(&self.stderr.join().unwrap_or_default()).into_owned(),
+            code: status.code(),
+            duration: self.start.elapsed(),
+        })
+    }
+
+    /// Whether the child has not exited yet
+    pub fn is_running(&self) -> Result<bool, String> {
+        Ok(!self.leader_exited()?)
+    }
+
+    /// Kills the child's process group, including processes it left in the
+    /// background after exiting, and collects what it left behind
+    pub fn kill(mut self) -> Result<Output, String> {
+        self.kill_group();
+        self.wait()
+    }
+
+    /// Waits at most `limit` for the child and for its output pipes to
+    /// close, which background processes it started may keep open. On
+    /// expiry the child's process group is killed and `None` is returned
+    pub fn wait_timeout(mut self, limit: Duration) -> Result<Option<Output>, String> {
+        let deadline = self.start + limit;
+        loop {
+            let drained = self.stdout.is_finished() && self.stderr.is_finished();
+            if drained && self.leader_exited()? {
+                return self.wait().map(Some);
+            }
+            if Instant::now() >= deadline {
+                self.kill_group();
+                self.wait()?;
+                return Ok(None);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Whether the child has exited, without reaping it: while it is a
+    /// zombie its pid, and so its process group id, can't be reused
+    fn leader_exited(&self) -> Result<bool, String> {
+        // SAFETY: waitid only writes to the zeroed siginfo_t it is given
+        unsafe {
+            let mut info: libc::siginfo_t = std::mem::zeroed();
+            let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+            if libc::waitid(libc::P_PID, self.child.id(), &mut info, flags) != 0 {
+                return Err(format!(
+                    "Failed to wait for shell: {}",
+                    io::Error::last_os_error()
+                ));
+            }
+            Ok(info.si_pid() != 0)
+        }
+    }
+
+    /// Kills the whole process group led by the child, so grandchildren
+    /// holding the output pipes go away too. The child must have been
+    /// started with `spawn_group`
+    fn kill_group(&mut self) {
+        let pid = self.child.id() as libc::pid_t;
+        // SAFETY: kill(2) has no memory safety requirements
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// Process groups started by `spawn_group` that have not been waited for,
+/// 0 marking a free slot. Plain atomics, as the signal handler reads them
+static GROUPS: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+
+/// Records `pgid` for `on_signal`, installing it on first use. Returns
+/// `None` when every slot is taken
+fn track_group(pgid: libc::pid_t) -> Option<usize> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only makes async-signal-safe calls
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+    });
+    GROUPS.iter().position(|slot| {
+        slot.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    })
+}
+
+/// Kills every tracked process group, then lets the signal end proj as it
+/// would have without the handler
+extern "C" fn on_signal(sig: libc::c_int) {
+    for slot in &GROUPS {
+        let pgid = slot.load(Ordering::SeqCst);
+        if pgid != 0 {
+            // SAFETY: kill(2) has no memory safety requirements
+            unsafe {
+                libc::kill(-pgid, libc::SIGKILL);
+            }
+        }
+    }
+    // SAFETY: signal(2) and raise(3) are async-signal-safe
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    }
+}
+
+/// Reads `src` to the end, copying each chunk to `tee` as it arrives
+fn capture(mut src: impl Read, mut tee: Option<impl Write>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        match src.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if let Some(out) = tee.as_mut() {
+                    let _ = out.write_all(&chunk[..n]);
+                    let _ = out.flush();
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+    buf
+}
+
+pub fn run(cmd: SysCommand, opts: &Options) -> Result<Output, String> {
+    Running::spawn(cmd, opts, None)?.wait()
+}
+
+/// Runs `stages` with each stage's stdout connected to the next one's
+/// stdin. The Output holds the last stage's stdout, every stage's stderr
+/// and, like `pipefail`, the rightmost non-zero exit code. The per-stage
+/// codes are returned alongside it
+pub fn run_pipeline(
+    stages: Vec<SysCommand>,
+    opts: &Options,
+) -> Result<(Output, Vec<Option<i32>>), String> {
+    if stages.is_empty() {
+        return Err("pipeline needs at least one stage".into());
+    }
+    let start = Instant::now();
+    let last = stages.len() - 1;
+    let mut children: Vec<Child> = Vec::new();
+    let mut stderrs = Vec::new();
+    let mut stdout = None;
+    let mut prev: Option<ChildStdout> = None;
+    for (i, mut cmd) in stages.into_iter().enumerate() {
+        // The first stage gets an empty stdin, like a single `shell` command
+        match prev.take() {
+            Some(prev) => cmd.stdin(prev),
+            None => cmd.stdin(Stdio::null()),
+        };
+        let spawned = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                for mut child in children {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(format!(
+                    "Failed to run '{}': {}",
+                    cmd.get_program().display(),
+                    e
+                ));
+            }
+        };
+        let stream = opts.stream;
+        let err = child.stderr.take().unwrap();
+        stderrs.push(thread::spawn(move || capture(err, stream.then(io::stderr))));
+        let out = child.stdout.take().unwrap();
+        if i == last {
+            stdout = Some(thread::spawn(move || capture(out, stream.then(io::stdout))));
+        } else {
+            prev = Some(out);
+        }
+        children.push(child);
+    }
+
+    let mut codes = Vec::new();
+    for mut child in children {
+        let status = child
+            .wait()
+            .map_err(|e| format!("Failed to wait for pipeline: {}", e))?;
+        codes.push(status.code());
+    }
+    let stdout = stdout.unwrap().join().unwrap_or_default();
+    let mut stderr = Vec::new();
+    for handle in stderrs {
+        stderr.extend(handle.join().unwrap_or_default());
+    }
+    let code = codes
+        .iter()
+        .rev()
+        .find(|c| **c != Some(0))
+        .copied()
+        .unwrap_or(Some(0));
+    let output = Output {
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        code,
+        duration: start.elapsed(),
+    };
+    Ok((output, codes))
+}
@@ -0,0 +1,23 @@
+use crate::Value;
+use ::toml::{Table, Value as Toml};
+
+pub fn parse(source: &str) -> Result<Value, String> {
+    let table: Table = source
+        .parse()
+        .map_err(|err| format!("toml_parse error: {err}"))?;
+    Ok(from_toml(Toml::Table(table)))
+}
+
+fn from_toml(toml: Toml) -> Value {
+    match toml {
+        Toml::String(s) => Value::Str(s),
+        Toml::Integer(i) => Value::Int(i),
+        Toml::Float(f) => Value::Float(f),
+        Toml::Boolean(b) => Value::Bool(b),
+        Toml::Datetime(d) => Value::Str(d.to_string()),
+        Toml::Array(items) => Value::List(items.into_iter().map(from_toml).collect()),
+        Toml::Table(table) => {
+            Value::Map(table.into_iter().map(|(k, v)| (k, from_toml(v))).collect())
+        }
+    }
+}